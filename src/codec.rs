@@ -5,20 +5,67 @@ use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Result;
-use std::io::Seek;
-use std::io::SeekFrom;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 
+use byteorder::ByteOrder;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 use byteorder::NetworkEndian;
+use crc::crc32;
 use ring::constant_time::verify_slices_are_equal;
 use ring::digest;
+use ring::hmac;
 use tokio_core::net::UdpCodec;
 
+use view::HEADER_LEN;
+use view::MessageView;
+use view::RawAttribute;
+
+// RFC 5389 magic cookie, first 32 bits of the ID field in Rfc5389 mode.
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// XOR mask applied to the CRC32 of a message to produce FINGERPRINT, chosen
+/// by the RFC so a FINGERPRINT attribute can't be mistaken for a correct
+/// MESSAGE-INTEGRITY HMAC by an old implementation.
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// The credentials a MESSAGE-INTEGRITY HMAC is keyed with.
+#[derive(Debug, Clone)]
+pub enum Credential {
+    /// Keyed directly with the password, as used for a one-off exchange
+    /// (e.g. a password obtained via SHARED-SECRET).
+    ShortTerm(Vec<u8>),
+    /// Keyed with `MD5(username ":" realm ":" password)`, as used once a
+    /// long-lived username/password has been established with the server.
+    LongTerm {
+        username: Vec<u8>,
+        realm: Vec<u8>,
+        password: Vec<u8>,
+    },
+}
+
+impl Credential {
+    fn key(&self) -> Vec<u8> {
+        match *self {
+            Credential::ShortTerm(ref password) => password.clone(),
+            Credential::LongTerm { ref username, ref realm, ref password } => {
+                let mut buf = Vec::with_capacity(username.len() + realm.len() + password.len() + 2);
+                buf.extend_from_slice(username);
+                buf.push(b':');
+                buf.extend_from_slice(realm);
+                buf.push(b':');
+                buf.extend_from_slice(password);
+
+                md5::compute(&buf).0.to_vec()
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Request {
     Bind(BindRequest),
@@ -37,6 +84,9 @@ pub struct BindRequest {
     pub response_address: Option<SocketAddr>,
     pub change_request: Option<ChangeRequest>,
     pub username: Option<Vec<u8>>,
+    /// When set, the codec signs the encoded request with a MESSAGE-INTEGRITY
+    /// attribute keyed from this credential.
+    pub credential: Option<Credential>,
 }
 
 impl BindRequest {
@@ -59,14 +109,19 @@ impl BindRequest {
     }
 }
 
+// Owned, semantic view of a parsed message, built from a borrowed
+// `MessageView` by `Repr::parse`; `emit` writes the body back out, header
+// and MESSAGE-INTEGRITY/FINGERPRINT trailer staying `StunCodec`'s job.
 #[derive(Debug)]
-pub enum Response {
+pub enum Repr {
     Bind(BindResponse),
-//    'BindErrorResponseMsg': BindErrorResponseMsg,
+    BindError(BindErrorResponse),
 //    'SharedSecretResponseMsg': SharedSecretResponseMsg,
 //    'SharedSecretErrorResponseMsg': SharedSecretErrorResponseMsg}
 }
 
+pub type Response = Repr;
+
 #[derive(Debug)]
 pub struct BindResponse {
     pub mapped_address: SocketAddr,
@@ -75,7 +130,41 @@ pub struct BindResponse {
     pub reflected_from: Option<SocketAddr>,
 }
 
-pub struct StunCodec;
+/// A BINDING_ERROR_RESPONSE, e.g. 401 Unauthorized or 420 Unknown Attribute.
+#[derive(Debug)]
+pub struct BindErrorResponse {
+    pub class: u8,
+    pub number: u8,
+    pub reason: String,
+    /// Attribute types the server didn't understand, set for class/number
+    /// 420 responses.
+    pub unknown_attributes: Vec<u16>,
+}
+
+// Classic is the opaque 128-bit transaction ID of RFC 3489; Rfc5389 carves
+// the magic cookie off the first 32 bits, leaving a 96-bit transaction ID.
+//
+// `StunCodec::{encode,decode}` correlate requests/responses with the `u64`
+// `trans_id` threaded through `UdpCodec::{In,Out}`, so in `Rfc5389` mode only
+// the low 64 bits of that 96-bit field are actually randomized: the 32 bits
+// between the magic cookie and `trans_id` are always encoded as zero, and
+// decode rejects any incoming message where they're non-zero. This client
+// never generates or needs to accept a message with real entropy in those
+// bits, but it does mean a fully RFC 5389-compliant peer -- one that fills
+// all 96 bits of an unsolicited message, rather than one this client
+// requested -- is rejected rather than accepted with those bits ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StunMode {
+    Classic,
+    Rfc5389,
+}
+
+pub struct StunCodec {
+    mode: StunMode,
+    /// Credential used to sign outgoing requests and verify MESSAGE-INTEGRITY
+    /// on incoming responses to them.
+    credential: Option<Credential>,
+}
 
 pub enum Attribute {
     MappedAddress(SocketAddr),
@@ -86,46 +175,83 @@ pub enum Attribute {
     ChangeRequest(ChangeRequest),
     MessageIntegrity([u8; 20]),
     Username(Vec<u8>),
+    XorMappedAddress(SocketAddr),
+    Fingerprint([u8; 4]),
+    ErrorCode { class: u8, number: u8, reason: String },
+    UnknownAttributes(Vec<u16>),
     UnknownOptional,
 }
 
 impl StunCodec {
     pub fn new() -> StunCodec {
-        StunCodec {}
+        StunCodec { mode: StunMode::Classic, credential: None }
+    }
+
+    pub fn with_mode(mode: StunMode) -> StunCodec {
+        StunCodec { mode, credential: None }
+    }
+
+    pub fn with_credential(mut self, credential: Credential) -> StunCodec {
+        self.credential = Some(credential);
+        self
+    }
+
+    fn set_msg_length(buf: &mut [u8], header_start: usize, len: u16) {
+        NetworkEndian::write_u16(&mut buf[header_start + 2..header_start + 4], len);
+    }
+
+    fn compute_message_integrity(key: &[u8], data: &[u8]) -> [u8; 20] {
+        let signing_key = hmac::SigningKey::new(&digest::SHA1, key);
+        let signature = hmac::sign(&signing_key, data);
+
+        let mut mac = [0; 20];
+        mac.copy_from_slice(signature.as_ref());
+        mac
+    }
+}
+
+impl Repr {
+    pub fn parse(view: &MessageView, key: &[u8; 16], credential: Option<&Credential>) -> Result<Repr> {
+        match view.msg_type() {
+            BINDING_RESPONSE => Self::parse_bind(view, key, credential).map(Repr::Bind),
+            BINDING_ERROR => Self::parse_bind_error(view, key, credential).map(Repr::BindError),
+            SHARED_SECRET_RESPONSE => Err(Error::new(ErrorKind::InvalidData, "Unsupported message type: SHARED_SECRET_RESPONSE!")),
+            SHARED_SECRET_ERROR => Err(Error::new(ErrorKind::InvalidData, "Unsupported message type: SHARED_SECRET_ERROR!")),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unknown message type!")),
+        }
     }
 
-    fn read_binding_response(msg: &[u8], mut c: &mut Cursor<&[u8]>) -> Result<BindResponse> {
+    fn parse_bind(view: &MessageView, key: &[u8; 16], credential: Option<&Credential>) -> Result<BindResponse> {
         let mut mapped_address = None;
         let mut source_address = None;
         let mut changed_address = None;
         let mut message_integrity = None;
         let mut reflected_from = None;
+        let mut fingerprint = None;
 
         let error = |reason| Err(Error::new(ErrorKind::InvalidData, reason));
 
-        loop {
-            let attr = Attribute::read(c);
-            match attr {
-                Ok(Attribute::MappedAddress(s))  if mapped_address.is_none()  => mapped_address = Some(s),
+        for attr in view.attributes() {
+            let RawAttribute { typ, start, body } = attr?;
+
+            match Attribute::parse(typ, body, key) {
+                Ok(Attribute::MappedAddress(s))    if mapped_address.is_none()  => mapped_address = Some(s),
+                Ok(Attribute::XorMappedAddress(s)) if mapped_address.is_none()  => mapped_address = Some(s),
                 Ok(Attribute::SourceAddress(s))  if source_address.is_none()  => source_address = Some(s),
                 Ok(Attribute::ChangedAddress(s)) if changed_address.is_none() => changed_address = Some(s),
                 Ok(Attribute::ReflectedFrom(s))  if reflected_from.is_none()  => reflected_from = Some(s),
                 Ok(Attribute::MessageIntegrity(h)) if message_integrity.is_none() => {
-                    message_integrity = Some(h)
+                    message_integrity = Some((start, h))
+                },
+                Ok(Attribute::Fingerprint(f)) if fingerprint.is_none() => {
+                    fingerprint = Some((start, f))
                 },
                 Ok(Attribute::UnknownOptional) => continue,
-                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
                 _ => return error("Unknown mandatory attribute!"),
             }
         }
 
-        if let Some(expected) = message_integrity {
-            let actual = digest::digest(&digest::SHA1, &msg[..msg.len() - 24]);
-
-            if verify_slices_are_equal(actual.as_ref(), &expected).is_err() {
-                return error("Message integrity violated!");
-            }
-        }
+        Self::verify_integrity(view, fingerprint, message_integrity, credential)?;
 
         Ok(BindResponse {
             mapped_address:  if let Some(a) = mapped_address { a } else { return error("MappedAddress missing!") },
@@ -134,26 +260,182 @@ impl StunCodec {
             reflected_from:  reflected_from,
         })
     }
+
+    /// Verifies FINGERPRINT and, if `credential` is given, MESSAGE-INTEGRITY
+    /// over the exact byte ranges of `view` they were computed over. Shared
+    /// between `parse_bind` and `parse_bind_error`, since a BINDING_ERROR
+    /// can be forged just as easily as a BINDING_RESPONSE if it isn't
+    /// checked the same way.
+    fn verify_integrity(
+        view: &MessageView,
+        fingerprint: Option<(usize, [u8; 4])>,
+        message_integrity: Option<(usize, [u8; 20])>,
+        credential: Option<&Credential>,
+    ) -> Result<()> {
+        let error = |reason| Err(Error::new(ErrorKind::InvalidData, reason));
+
+        if let Some((pos, expected)) = fingerprint {
+            let actual = crc32::checksum_ieee(&view.as_bytes()[..pos]) ^ FINGERPRINT_XOR;
+
+            if actual != NetworkEndian::read_u32(&expected) {
+                return error("Fingerprint mismatch!");
+            }
+        }
+
+        if let Some(credential) = credential {
+            let (pos, expected) = match message_integrity {
+                Some(mi) => mi,
+                None => return error("MESSAGE-INTEGRITY missing!"),
+            };
+
+            // The signer hashed the message as it read with only the
+            // header and the attributes up to and including
+            // MESSAGE-INTEGRITY itself present, so the header's length
+            // field has to be patched back to that value before
+            // re-hashing -- any attributes appended afterwards (e.g.
+            // FINGERPRINT) inflate the length the message actually
+            // carries on the wire.
+            let mut signed = view.as_bytes()[..pos].to_vec();
+            let len_through_mi = (pos - HEADER_LEN + 24) as u16;
+            NetworkEndian::write_u16(&mut signed[2..4], len_through_mi);
+
+            let actual = StunCodec::compute_message_integrity(&credential.key(), &signed);
+
+            if verify_slices_are_equal(&actual, &expected).is_err() {
+                return error("Message integrity violated!");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_bind_error(view: &MessageView, key: &[u8; 16], credential: Option<&Credential>) -> Result<BindErrorResponse> {
+        let mut error_code = None;
+        let mut unknown_attributes = Vec::new();
+        let mut message_integrity = None;
+        let mut fingerprint = None;
+
+        let error = |reason| Err(Error::new(ErrorKind::InvalidData, reason));
+
+        for attr in view.attributes() {
+            let RawAttribute { typ, start, body } = attr?;
+
+            match Attribute::parse(typ, body, key) {
+                Ok(Attribute::ErrorCode { class, number, reason }) if error_code.is_none() => {
+                    error_code = Some((class, number, reason))
+                },
+                Ok(Attribute::UnknownAttributes(types)) => unknown_attributes = types,
+                Ok(Attribute::Fingerprint(f)) if fingerprint.is_none() => {
+                    fingerprint = Some((start, f))
+                },
+                Ok(Attribute::MessageIntegrity(h)) if message_integrity.is_none() => {
+                    message_integrity = Some((start, h))
+                },
+                Ok(Attribute::UnknownOptional) => continue,
+                _ => return error("Unknown mandatory attribute!"),
+            }
+        }
+
+        let (class, number, reason) = match error_code {
+            Some(v) => v,
+            None => return error("ErrorCode missing!"),
+        };
+
+        Self::verify_integrity(view, fingerprint, message_integrity, credential)?;
+
+        Ok(BindErrorResponse {
+            class: class,
+            number: number,
+            reason: reason,
+            unknown_attributes: unknown_attributes,
+        })
+    }
+
+    pub fn emit(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match *self {
+            Repr::Bind(ref r) => {
+                Attribute::MappedAddress(r.mapped_address).encode(buf)?;
+                Attribute::SourceAddress(r.source_address).encode(buf)?;
+                Attribute::ChangedAddress(r.changed_address).encode(buf)?;
+
+                if let Some(reflected_from) = r.reflected_from {
+                    Attribute::ReflectedFrom(reflected_from).encode(buf)?;
+                }
+            },
+            Repr::BindError(ref e) => {
+                Attribute::ErrorCode {
+                    class: e.class,
+                    number: e.number,
+                    reason: e.reason.clone(),
+                }.encode(buf)?;
+
+                if !e.unknown_attributes.is_empty() {
+                    Attribute::UnknownAttributes(e.unknown_attributes.clone()).encode(buf)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
 }
 
 impl Attribute {
-    fn read(mut c: &mut Cursor<&[u8]>) -> Result<Attribute> {
-        let typ = c.read_u16::<NetworkEndian>()?;
-        let len = c.read_u16::<NetworkEndian>()?;
+    // `body` is already length-delimited and padding-stripped, per `view::Attributes`.
+    fn parse(typ: u16, body: &[u8], key: &[u8; 16]) -> Result<Attribute> {
+        let mut c = Cursor::new(body);
 
         match typ {
-            MAPPED_ADDRESS    => Ok(Attribute::MappedAddress(Self::read_address(&mut c)?)),
-            RESPONSE_ADDRESS  => Ok(Attribute::ResponseAddress(Self::read_address(&mut c)?)),
-            CHANGED_ADDRESS   => Ok(Attribute::ChangedAddress(Self::read_address(&mut c)?)),
-            SOURCE_ADDRESS    => Ok(Attribute::SourceAddress(Self::read_address(&mut c)?)),
-            REFLECTED_FROM    => Ok(Attribute::ReflectedFrom(Self::read_address(&mut c)?)),
+            MAPPED_ADDRESS     => Ok(Attribute::MappedAddress(Self::read_address(&mut c)?)),
+            RESPONSE_ADDRESS   => Ok(Attribute::ResponseAddress(Self::read_address(&mut c)?)),
+            CHANGED_ADDRESS    => Ok(Attribute::ChangedAddress(Self::read_address(&mut c)?)),
+            SOURCE_ADDRESS     => Ok(Attribute::SourceAddress(Self::read_address(&mut c)?)),
+            REFLECTED_FROM     => Ok(Attribute::ReflectedFrom(Self::read_address(&mut c)?)),
+            XOR_MAPPED_ADDRESS => Ok(Attribute::XorMappedAddress(Self::read_xor_address(&mut c, key)?)),
             MESSAGE_INTEGRITY => {
+                if body.len() != 20 {
+                    return Err(Error::new(ErrorKind::InvalidData, "MESSAGE-INTEGRITY has the wrong length"));
+                }
+
                 let mut hash = [0; 20];
-                c.read_exact(&mut hash)?;
+                hash.copy_from_slice(body);
                 Ok(Attribute::MessageIntegrity(hash))
             },
+            FINGERPRINT => {
+                if body.len() != 4 {
+                    return Err(Error::new(ErrorKind::InvalidData, "FINGERPRINT has the wrong length"));
+                }
+
+                let mut crc = [0; 4];
+                crc.copy_from_slice(body);
+                Ok(Attribute::Fingerprint(crc))
+            },
+            ERROR_CODE => {
+                if body.len() < 4 {
+                    return Err(Error::new(ErrorKind::InvalidData, "ERROR-CODE too short"));
+                }
+
+                let class = body[2];
+                let number = body[3];
+
+                let reason = String::from_utf8(body[4..].to_vec())
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "ERROR-CODE reason is not valid UTF-8"))?;
+
+                Ok(Attribute::ErrorCode { class: class, number: number, reason: reason })
+            },
+            UNKNOWN_ATTRIBUTES => {
+                if body.len() % 2 != 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "UNKNOWN-ATTRIBUTES has an odd length"));
+                }
+
+                let types = body.chunks(2).map(NetworkEndian::read_u16).collect();
+                Ok(Attribute::UnknownAttributes(types))
+            },
             CHANGE_REQUEST    => {
-                match c.read_u32::<NetworkEndian>()? {
+                if body.len() != 4 {
+                    return Err(Error::new(ErrorKind::InvalidData, "CHANGE_REQUEST has the wrong length"));
+                }
+
+                match NetworkEndian::read_u32(body) {
                     CHANGE_REQUEST_IP          => Ok(Attribute::ChangeRequest(ChangeRequest::Ip)),
                     CHANGE_REQUEST_PORT        => Ok(Attribute::ChangeRequest(ChangeRequest::Port)),
                     CHANGE_REQUEST_IP_AND_PORT => Ok(Attribute::ChangeRequest(ChangeRequest::IpAndPort)),
@@ -161,40 +443,67 @@ impl Attribute {
                 }
             },
             _ if typ <= 0x7fff => Err(Error::new(ErrorKind::InvalidData, "Unknown mandatory field")),
-            _ => {
-                c.seek(SeekFrom::Current(len as i64))?;
-                Ok(Attribute::UnknownOptional)
-            },
+            _ => Ok(Attribute::UnknownOptional),
         }
     }
 
     fn read_address(c: &mut Cursor<&[u8]>) -> Result<SocketAddr> {
         let _ = c.read_u8()?; // ignored
-        let typ = c.read_u8()?;
+        let family = c.read_u8()?;
         let port = c.read_u16::<NetworkEndian>()?;
-        let addr = c.read_u32::<NetworkEndian>()?;
 
-        if typ != 0x01 {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid address family"));
+        match family {
+            0x01 => {
+                let addr = c.read_u32::<NetworkEndian>()?;
+                Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(addr)), port))
+            },
+            0x02 => {
+                let mut octets = [0; 16];
+                c.read_exact(&mut octets)?;
+                Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            },
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid address family")),
         }
+    }
 
-        let b0 = ((addr & 0xff000000) >> 24) as u8;
-        let b1 = ((addr & 0x00ff0000) >> 16) as u8;
-        let b2 = ((addr & 0x0000ff00) >>  8) as u8;
-        let b3 = ((addr & 0x000000ff) >>  0) as u8;
-        let ip = IpAddr::V4(Ipv4Addr::new(b0, b1, b2, b3));
-
-        Ok(SocketAddr::new(ip, port))
+    // X-Port/X-Address pair; IPv4 XORs against just the first four bytes of
+    // `key` (cookie + transaction ID), IPv6 against the full 16.
+    fn read_xor_address(c: &mut Cursor<&[u8]>, key: &[u8; 16]) -> Result<SocketAddr> {
+        let _ = c.read_u8()?; // ignored
+        let family = c.read_u8()?;
+        let xport = c.read_u16::<NetworkEndian>()?;
+        let port = xport ^ NetworkEndian::read_u16(&key[0..2]);
+
+        match family {
+            0x01 => {
+                let xaddr = c.read_u32::<NetworkEndian>()?;
+                let addr = xaddr ^ NetworkEndian::read_u32(&key[0..4]);
+                Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(addr)), port))
+            },
+            0x02 => {
+                let mut xoctets = [0; 16];
+                c.read_exact(&mut xoctets)?;
+                let mut octets = [0; 16];
+                for i in 0..16 {
+                    octets[i] = xoctets[i] ^ key[i];
+                }
+                Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            },
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid address family")),
+        }
     }
 
     fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
-        let (typ, opaque) = match *self {
-            Attribute::MappedAddress(ref s)    => (MAPPED_ADDRESS,    Self::encode_address(s)?),
-            Attribute::ResponseAddress(ref s)  => (RESPONSE_ADDRESS,  Self::encode_address(s)?),
-            Attribute::ChangedAddress(ref s)   => (CHANGED_ADDRESS,   Self::encode_address(s)?),
-            Attribute::SourceAddress(ref s)    => (SOURCE_ADDRESS,    Self::encode_address(s)?),
-            Attribute::ReflectedFrom(ref s)    => (REFLECTED_FROM,    Self::encode_address(s)?),
-            Attribute::MessageIntegrity(ref h) => (MESSAGE_INTEGRITY, h.to_vec()),
+        // declared_len differs from opaque.len() only for ERROR-CODE and
+        // UNKNOWN-ATTRIBUTES, whose padded bytes aren't the wire length.
+        let (typ, declared_len, opaque) = match *self {
+            Attribute::MappedAddress(ref s)    => { let o = Self::encode_address(s)?; (MAPPED_ADDRESS,    o.len(), o) },
+            Attribute::ResponseAddress(ref s)  => { let o = Self::encode_address(s)?; (RESPONSE_ADDRESS,  o.len(), o) },
+            Attribute::ChangedAddress(ref s)   => { let o = Self::encode_address(s)?; (CHANGED_ADDRESS,   o.len(), o) },
+            Attribute::SourceAddress(ref s)    => { let o = Self::encode_address(s)?; (SOURCE_ADDRESS,    o.len(), o) },
+            Attribute::ReflectedFrom(ref s)    => { let o = Self::encode_address(s)?; (REFLECTED_FROM,    o.len(), o) },
+            Attribute::MessageIntegrity(ref h) => (MESSAGE_INTEGRITY, h.len(), h.to_vec()),
+            Attribute::Fingerprint(ref f) => (FINGERPRINT, f.len(), f.to_vec()),
             Attribute::Username(ref u) => {
                 let total_len = (4.0*(u.len() as f64 / 4.0).ceil()) as usize;
                 let padding_len = total_len - u.len();
@@ -206,14 +515,23 @@ impl Attribute {
                 }
                 assert_eq!(buf.len(), total_len);
 
-                (USERNAME, buf.clone())
+                (USERNAME, buf.len(), buf.clone())
             },
-            Attribute::ChangeRequest(ref c) => (CHANGE_REQUEST, Self::encode_change_request(c)?),
+            Attribute::ChangeRequest(ref c) => { let o = Self::encode_change_request(c)?; (CHANGE_REQUEST, o.len(), o) },
+            Attribute::ErrorCode { class, number, ref reason } => {
+                let (len, o) = Self::encode_error_code(class, number, reason)?;
+                (ERROR_CODE, len, o)
+            },
+            Attribute::UnknownAttributes(ref types) => {
+                let (len, o) = Self::encode_unknown_attributes(types)?;
+                (UNKNOWN_ATTRIBUTES, len, o)
+            },
+            Attribute::XorMappedAddress(_) => unreachable!("XOR-MAPPED-ADDRESS is server-to-client only"),
             Attribute::UnknownOptional => unreachable!(),
         };
 
         buf.write_u16::<NetworkEndian>(typ)?;
-        buf.write_u16::<NetworkEndian>(opaque.len() as u16)?;
+        buf.write_u16::<NetworkEndian>(declared_len as u16)?;
         buf.write_all(&opaque[..])?;
 
         Ok(())
@@ -231,18 +549,57 @@ impl Attribute {
         Ok(buf)
     }
 
-    fn encode_address(addr: &SocketAddr) -> Result<Vec<u8>> {
-        let mut buf = Vec::with_capacity(8);
-        buf.write_u8(0x00)?;
-        buf.write_u8(0x01)?;
+    // Returns the unpadded length (for the wire length field) alongside the
+    // zero-padded bytes (to keep the next attribute 4-byte aligned).
+    fn encode_error_code(class: u8, number: u8, reason: &str) -> Result<(usize, Vec<u8>)> {
+        let unpadded_len = 4 + reason.len();
+        let total_len = (4.0*(unpadded_len as f64 / 4.0).ceil()) as usize;
+
+        let mut buf = Vec::with_capacity(total_len);
+        buf.write_u16::<NetworkEndian>(0x0000)?; // reserved
+        buf.write_u8(class)?;
+        buf.write_u8(number)?;
+        buf.write_all(reason.as_bytes())?;
+        for _ in 0..total_len - unpadded_len {
+            buf.write_u8(0x00)?;
+        }
+
+        Ok((unpadded_len, buf))
+    }
+
+    fn encode_unknown_attributes(types: &[u16]) -> Result<(usize, Vec<u8>)> {
+        let unpadded_len = types.len() * 2;
+        let total_len = (4.0*(unpadded_len as f64 / 4.0).ceil()) as usize;
+
+        let mut buf = Vec::with_capacity(total_len);
+        for typ in types {
+            buf.write_u16::<NetworkEndian>(*typ)?;
+        }
+        for _ in 0..total_len - unpadded_len {
+            buf.write_u8(0x00)?;
+        }
 
-        if let SocketAddr::V4(ref addr) = *addr {
-            buf.write_u16::<NetworkEndian>(addr.port())?;
-            buf.write_all(&addr.ip().octets()[..])?;
+        Ok((unpadded_len, buf))
+    }
 
-            Ok(buf)
-        } else {
-            Err(Error::new(ErrorKind::InvalidInput, "STUN does not support IPv6"))
+    fn encode_address(addr: &SocketAddr) -> Result<Vec<u8>> {
+        match *addr {
+            SocketAddr::V4(ref addr) => {
+                let mut buf = Vec::with_capacity(8);
+                buf.write_u8(0x00)?;
+                buf.write_u8(0x01)?;
+                buf.write_u16::<NetworkEndian>(addr.port())?;
+                buf.write_all(&addr.ip().octets()[..])?;
+                Ok(buf)
+            },
+            SocketAddr::V6(ref addr) => {
+                let mut buf = Vec::with_capacity(20);
+                buf.write_u8(0x00)?;
+                buf.write_u8(0x02)?;
+                buf.write_u16::<NetworkEndian>(addr.port())?;
+                buf.write_all(&addr.ip().octets()[..])?;
+                Ok(buf)
+            },
         }
     }
 }
@@ -265,6 +622,8 @@ const MESSAGE_INTEGRITY:u16  = 0x0008;
 const ERROR_CODE:u16         = 0x0009;
 const UNKNOWN_ATTRIBUTES:u16 = 0x000a;
 const REFLECTED_FROM:u16     = 0x000b;
+const XOR_MAPPED_ADDRESS:u16 = 0x0020;
+const FINGERPRINT:u16        = 0x8028;
 
 const CHANGE_REQUEST_IP:u32          = 0x20;
 const CHANGE_REQUEST_PORT:u32        = 0x40;
@@ -275,57 +634,85 @@ impl UdpCodec for StunCodec {
     type Out = (u64, SocketAddr, Request);
 
     fn decode(&mut self, _: &SocketAddr, msg: &[u8]) -> Result<Self::In> {
-        let mut c = Cursor::new(msg);
+        let view = MessageView::new(msg)?;
 
-        let msg_type = c.read_u16::<NetworkEndian>()?;
-        let _ = c.read_u16::<NetworkEndian>()?; // msg_len
-        let trans_id1 = c.read_u64::<NetworkEndian>()?;
-        let trans_id2 = c.read_u64::<NetworkEndian>()?;
+        let txn = view.transaction_id();
+        let trans_id1 = NetworkEndian::read_u64(&txn[0..8]);
+        let trans_id2 = NetworkEndian::read_u64(&txn[8..16]);
+
+        match self.mode {
+            StunMode::Classic => {
+                if trans_id1 != 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Invalid transaction ID!"));
+                }
+            },
+            StunMode::Rfc5389 => {
+                let cookie = (trans_id1 >> 32) as u32;
+                let txn_id_hi = (trans_id1 & 0xffff_ffff) as u32;
 
-        if trans_id1 != 0 {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid transaction ID!"));
+                if cookie != MAGIC_COOKIE || txn_id_hi != 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Invalid magic cookie!"));
+                }
+            },
         }
 
-        let res = match msg_type {
-            BINDING_RESPONSE => Self::read_binding_response(msg, &mut c).map(|r| Response::Bind(r)),
-            BINDING_ERROR => unimplemented!(),
-            SHARED_SECRET_RESPONSE => unimplemented!(),
-            SHARED_SECRET_ERROR => unimplemented!(),
-            _ => return Err(Error::new(ErrorKind::InvalidData, "Unknown message type!")),
-        };
+        // Magic cookie followed by the 96-bit transaction ID, used to XOR
+        // XOR-MAPPED-ADDRESS regardless of which mode this message is in.
+        // key[4..8] is left zero along with the corresponding bits of the
+        // transaction ID itself -- see the note on `StunMode::Rfc5389`.
+        let mut key = [0; 16];
+        NetworkEndian::write_u32(&mut key[0..4], MAGIC_COOKIE);
+        NetworkEndian::write_u64(&mut key[8..16], trans_id2);
 
-        res.map(|v| (trans_id2, v))
+        Repr::parse(&view, &key, self.credential.as_ref()).map(|v| (trans_id2, v))
     }
 
     fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
         let (trans_id, dst, req) = msg;
 
-        let (typ, m) = match req {
-            Request::Bind(bind) => (BINDING_REQUEST, bind.encode().unwrap()),
+        let (typ, m, credential) = match req {
+            Request::Bind(bind) => {
+                let credential = bind.credential.clone().or_else(|| self.credential.clone());
+                (BINDING_REQUEST, bind.encode().unwrap(), credential)
+            },
             _ => unimplemented!(),
         };
 
+        let start = buf.len();
+
         buf.write_u16::<NetworkEndian>(typ).unwrap();
-//        buf.write_u16::<NetworkEndian>(m.len() as u16 + 24).unwrap();
         buf.write_u16::<NetworkEndian>(m.len() as u16).unwrap();
-        buf.write_u64::<NetworkEndian>(0x0).unwrap();
+
+        match self.mode {
+            StunMode::Classic => buf.write_u64::<NetworkEndian>(0x0).unwrap(),
+            StunMode::Rfc5389 => {
+                buf.write_u32::<NetworkEndian>(MAGIC_COOKIE).unwrap();
+                buf.write_u32::<NetworkEndian>(0x0).unwrap(); // high 32 bits of the 96-bit transaction ID
+            },
+        }
         buf.write_u64::<NetworkEndian>(trans_id).unwrap();
         buf.write_all(&m[..]).unwrap();
 
-        /*
-            TODO
-        let mut copy = buf.clone();
-        while copy.len() % 64 != 0 {
-            copy.write_u8(0).unwrap();
+        if let Some(credential) = credential {
+            // MESSAGE-INTEGRITY is signed over the message as it will read
+            // once the attribute itself has been appended, so the header's
+            // length field is patched to that size before signing.
+            let len = (buf.len() - start - 20) as u16 + 24;
+            Self::set_msg_length(buf, start, len);
+
+            let mac = Self::compute_message_integrity(&credential.key(), &buf[start..]);
+            Attribute::MessageIntegrity(mac).encode(buf).unwrap();
         }
-        println!("{}", copy.len());
 
-        let mut hash = [0; 20];
-        let digest = digest::digest(&digest::SHA1, &copy[..]);
-        hash.copy_from_slice(digest.as_ref());
-        let message_integrity = Attribute::MessageIntegrity(hash);
-        message_integrity.encode(buf).unwrap();
-            */
+        // FINGERPRINT covers the whole message, header length included, so it
+        // is always the last attribute.
+        let len = (buf.len() - start - 20) as u16 + 8;
+        Self::set_msg_length(buf, start, len);
+
+        let crc = crc32::checksum_ieee(&buf[start..]) ^ FINGERPRINT_XOR;
+        let mut fingerprint = [0; 4];
+        NetworkEndian::write_u32(&mut fingerprint, crc);
+        Attribute::Fingerprint(fingerprint).encode(buf).unwrap();
 
         dst
     }
@@ -356,16 +743,15 @@ mod tests {
             response_address: None,
             change_request: Some(ChangeRequest::IpAndPort),
             username: Some(b"foo".to_vec()),
+            credential: None,
         };
 
         let addr = "0.0.0.0:0".parse().unwrap();
         let mut actual = Vec::new();
-        let _ = StunCodec.encode((0x123456789, addr, Request::Bind(req)), &mut actual); // dst
+        let _ = StunCodec::new().encode((0x123456789, addr, Request::Bind(req)), &mut actual); // dst
 
-        // TODO: sha1
         let expected = vec![
-//            0x00, 0x01, 0x00, 0x14, // type, len
-            0x00, 0x01, 0x00, 0x10, // type, len
+            0x00, 0x01, 0x00, 0x18, // type, len (includes FINGERPRINT)
             0x00, 0x00, 0x00, 0x00, // transaction id
             0x00, 0x00, 0x00, 0x00, //  ...
             0x00, 0x00, 0x00, 0x01, //  ...
@@ -374,15 +760,299 @@ mod tests {
             0x00, 0x00, 0x00, 0x60, //  ip and port
             0x00, 0x06, 0x00, 0x04, // username
             0x66, 0x6f, 0x6f, 0x00, //  "foo"
+            0x80, 0x28, 0x00, 0x04, // fingerprint, len
+            0x63, 0xd4, 0x41, 0xef, //  crc32(...) ^ 0x5354554e
+        ];
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn encode_binding_request_signed_with_credential() {
+        let credential = Credential::ShortTerm(b"password".to_vec());
+
+        let req = BindRequest {
+            credential: Some(credential.clone()),
+            ..BindRequest::default()
+        };
+
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let mut actual = Vec::new();
+        let _ = StunCodec::new().encode((0, addr, Request::Bind(req)), &mut actual);
+
+        // MESSAGE-INTEGRITY (24 bytes) + FINGERPRINT (8 bytes); the length
+        // field doesn't count the 20-byte header.
+        assert_eq!(NetworkEndian::read_u16(&actual[2..4]), 32);
+
+        // MESSAGE-INTEGRITY is the attribute right before FINGERPRINT, i.e.
+        // the last 32 bytes minus the 8-byte FINGERPRINT TLV.
+        let mi_start = actual.len() - 8 - 24;
+        assert_eq!(&actual[mi_start..mi_start + 4], &[0x00, 0x08, 0x00, 0x14]);
+
+        let mut signed = actual[..mi_start].to_vec();
+        let len_through_mi = (mi_start - 20) as u16 + 24;
+        StunCodec::set_msg_length(&mut signed, 0, len_through_mi);
+        let expected_mac = StunCodec::compute_message_integrity(&credential.key(), &signed);
+
+        assert_eq!(&actual[mi_start + 4..mi_start + 24], &expected_mac[..]);
+    }
+
+    #[test]
+    fn message_integrity_short_term() {
+        let credential = Credential::ShortTerm(b"password".to_vec());
+        let key = credential.key();
+        let data = b"hello world";
+
+        let mac = StunCodec::compute_message_integrity(&key, data);
+
+        // An HMAC must be reproducible from the same key and data, and must
+        // depend on the key: flip a byte of the password and it changes.
+        assert_eq!(mac, StunCodec::compute_message_integrity(&key, data));
+
+        let other_key = Credential::ShortTerm(b"Password".to_vec()).key();
+        assert_ne!(mac[..], StunCodec::compute_message_integrity(&other_key, data)[..]);
+    }
+
+    #[test]
+    fn decode_binding_response_with_message_integrity_and_fingerprint() {
+        let credential = Credential::ShortTerm(b"password".to_vec());
+
+        let mut buf = Vec::new();
+        buf.write_u16::<NetworkEndian>(BINDING_RESPONSE).unwrap();
+        buf.write_u16::<NetworkEndian>(0).unwrap(); // patched below
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+
+        Attribute::MappedAddress("127.0.0.1:1234".parse().unwrap()).encode(&mut buf).unwrap();
+        Attribute::SourceAddress("127.0.0.1:3478".parse().unwrap()).encode(&mut buf).unwrap();
+        Attribute::ChangedAddress("127.0.0.1:3479".parse().unwrap()).encode(&mut buf).unwrap();
+
+        // Sign over the message as it reads through MESSAGE-INTEGRITY, then
+        // append FINGERPRINT over the final length -- exactly what a
+        // compliant server does, and what `StunCodec::encode` does for
+        // requests.
+        let len_through_mi = (buf.len() - 20) as u16 + 24;
+        StunCodec::set_msg_length(&mut buf, 0, len_through_mi);
+        let mac = StunCodec::compute_message_integrity(&credential.key(), &buf);
+        Attribute::MessageIntegrity(mac).encode(&mut buf).unwrap();
+
+        let len_through_fingerprint = (buf.len() - 20) as u16 + 8;
+        StunCodec::set_msg_length(&mut buf, 0, len_through_fingerprint);
+        let crc = crc32::checksum_ieee(&buf) ^ FINGERPRINT_XOR;
+        let mut fingerprint = [0; 4];
+        NetworkEndian::write_u32(&mut fingerprint, crc);
+        Attribute::Fingerprint(fingerprint).encode(&mut buf).unwrap();
+
+        let mut codec = StunCodec::new().with_credential(credential);
+        let from = "127.0.0.1:3478".parse().unwrap();
+        let (_, response) = codec.decode(&from, &buf).unwrap();
+
+        match response {
+            Response::Bind(_) => {},
+            _ => panic!("expected a Bind response"),
+        }
+    }
+
+    #[test]
+    fn decode_xor_mapped_address_rfc5769_vector() {
+        // RFC 5769 2.2, IPv4 response: 192.0.2.1:32853 XORed against the
+        // magic cookie.
+        let mut key = [0; 16];
+        NetworkEndian::write_u32(&mut key[0..4], MAGIC_COOKIE);
+
+        let body = [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        let attr = Attribute::parse(XOR_MAPPED_ADDRESS, &body, &key).unwrap();
+
+        match attr {
+            Attribute::XorMappedAddress(addr) => assert_eq!(addr, "192.0.2.1:32853".parse().unwrap()),
+            _ => panic!("expected XorMappedAddress"),
+        }
+    }
+
+    #[test]
+    fn decode_xor_mapped_address_ipv6_roundtrip() {
+        let mut key = [0; 16];
+        NetworkEndian::write_u32(&mut key[0..4], MAGIC_COOKIE);
+        for (i, b) in (1..=12).enumerate() {
+            key[4 + i] = b;
+        }
+
+        let addr: SocketAddr = "[2001:db8::1:2]:34567".parse().unwrap();
+        let (ip, port) = match addr {
+            SocketAddr::V6(ref a) => (a.ip().octets(), a.port()),
+            _ => unreachable!(),
+        };
+
+        let mut body = Vec::with_capacity(20);
+        body.write_u8(0x00).unwrap();
+        body.write_u8(0x02).unwrap();
+        body.write_u16::<NetworkEndian>(port ^ NetworkEndian::read_u16(&key[0..2])).unwrap();
+        for i in 0..16 {
+            body.write_u8(ip[i] ^ key[i]).unwrap();
+        }
+
+        let attr = Attribute::parse(XOR_MAPPED_ADDRESS, &body, &key).unwrap();
+
+        match attr {
+            Attribute::XorMappedAddress(decoded) => assert_eq!(decoded, addr),
+            _ => panic!("expected XorMappedAddress"),
+        }
+    }
+
+    #[test]
+    fn decode_rejects_wrong_magic_cookie_in_rfc5389_mode() {
+        let mut buf = Vec::new();
+        buf.write_u16::<NetworkEndian>(BINDING_RESPONSE).unwrap();
+        buf.write_u16::<NetworkEndian>(0).unwrap();
+        buf.write_u64::<NetworkEndian>(0).unwrap(); // cookie should be 0x2112a442, not 0
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+
+        let from = "127.0.0.1:3478".parse().unwrap();
+        let mut codec = StunCodec::with_mode(StunMode::Rfc5389);
+
+        match codec.decode(&from, &buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            other => panic!("expected a rejected bad cookie, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn encode_error_code() {
+        let mut buf = Vec::new();
+
+        let attr = Attribute::ErrorCode { class: 4, number: 20, reason: "Unknown Attribute".to_string() };
+        attr.encode(&mut buf).unwrap();
+
+        let expected = vec![
+            0x00, 0x09, 0x00, 0x15, // error-code, len
+            0x00, 0x00, 0x04, 0x14, //  reserved, class 4, number 20
+            0x55, 0x6e, 0x6b, 0x6e, //  "Unkn
+            0x6f, 0x77, 0x6e, 0x20, //   own
+            0x41, 0x74, 0x74, 0x72, //   Attr
+            0x69, 0x62, 0x75, 0x74, //   ibut
+            0x65, 0x00, 0x00, 0x00, //   e" + padding
+        ];
+
+        assert_eq!(expected, buf);
+    }
 
-            /*0x00, 0x08, 0x00, 0x14, // message integrity
-            0x89, 0x4f, 0xef, 0x24, //  sha1
-            0xd5, 0x81, 0x45, 0x66, //  ...
-            0x8b, 0xa8, 0x27, 0xf0, //  ...
-            0xf8, 0x1e, 0x54, 0x98, //  ...
-            0xf7, 0x19, 0x52, 0x04, //  ...
-            */];
+    #[test]
+    fn roundtrip_bind_error_through_view() {
+        let msg = vec![
+            0x01, 0x11, 0x00, 0x24, // type, len
+            0x00, 0x00, 0x00, 0x00, // transaction id
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x09, 0x00, 0x15, // error-code, len
+            0x00, 0x00, 0x04, 0x14, //  reserved, class 4, number 20
+            0x55, 0x6e, 0x6b, 0x6e, //  "Unkn
+            0x6f, 0x77, 0x6e, 0x20, //   own
+            0x41, 0x74, 0x74, 0x72, //   Attr
+            0x69, 0x62, 0x75, 0x74, //   ibut
+            0x65, 0x00, 0x00, 0x00, //   e" + padding
+            0x00, 0x0a, 0x00, 0x02, // unknown-attributes, len
+            0x00, 0x01, 0x00, 0x00, //  type 0x0001 + padding
+        ];
+
+        let view = ::view::MessageView::new(&msg).unwrap();
+        let repr = Repr::parse(&view, &[0; 16], None).unwrap();
+
+        let mut body = Vec::new();
+        repr.emit(&mut body).unwrap();
+
+        // emit() only writes the body, not the 20-byte header.
+        assert_eq!(&msg[20..], &body[..]);
+    }
 
-            assert_eq!(expected, actual);
+    #[test]
+    fn decode_binding_error() {
+        let msg = vec![
+            0x01, 0x11, 0x00, 0x24, // type, len
+            0x00, 0x00, 0x00, 0x00, // transaction id
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x09, 0x00, 0x15, // error-code, len
+            0x00, 0x00, 0x04, 0x14, //  reserved, class 4, number 20
+            0x55, 0x6e, 0x6b, 0x6e, //  "Unkn
+            0x6f, 0x77, 0x6e, 0x20, //   own
+            0x41, 0x74, 0x74, 0x72, //   Attr
+            0x69, 0x62, 0x75, 0x74, //   ibut
+            0x65, 0x00, 0x00, 0x00, //   e" + padding
+            0x00, 0x0a, 0x00, 0x02, // unknown-attributes, len
+            0x00, 0x01, 0x00, 0x00, //  type 0x0001 + padding
+        ];
+
+        let from = "127.0.0.1:3478".parse().unwrap();
+        let (_, response) = StunCodec::new().decode(&from, &msg).unwrap();
+
+        match response {
+            Response::BindError(e) => {
+                assert_eq!(e.class, 4);
+                assert_eq!(e.number, 20);
+                assert_eq!(e.reason, "Unknown Attribute");
+                assert_eq!(e.unknown_attributes, vec![0x0001]);
+            },
+            _ => panic!("expected a BindError response"),
+        }
+    }
+
+    #[test]
+    fn decode_binding_error_rejects_forged_message_integrity() {
+        let credential = Credential::ShortTerm(b"password".to_vec());
+
+        let mut buf = Vec::new();
+        buf.write_u16::<NetworkEndian>(BINDING_ERROR).unwrap();
+        buf.write_u16::<NetworkEndian>(0).unwrap(); // patched below
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+
+        Attribute::ErrorCode { class: 4, number: 20, reason: "Unknown Attribute".to_string() }
+            .encode(&mut buf).unwrap();
+
+        // Sign with a key the server never used, simulating an attacker who
+        // forged the ERROR-CODE but can't produce a matching HMAC.
+        let len_through_mi = (buf.len() - 20) as u16 + 24;
+        StunCodec::set_msg_length(&mut buf, 0, len_through_mi);
+        let forged_mac = StunCodec::compute_message_integrity(b"not the real key", &buf);
+        Attribute::MessageIntegrity(forged_mac).encode(&mut buf).unwrap();
+
+        let mut codec = StunCodec::new().with_credential(credential);
+        let from = "127.0.0.1:3478".parse().unwrap();
+
+        match codec.decode(&from, &buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            other => panic!("expected a rejected forged BindError, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn decode_rejects_response_missing_message_integrity_when_credential_configured() {
+        let credential = Credential::ShortTerm(b"password".to_vec());
+
+        let mut buf = Vec::new();
+        buf.write_u16::<NetworkEndian>(BINDING_RESPONSE).unwrap();
+        buf.write_u16::<NetworkEndian>(0).unwrap(); // patched below
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+        buf.write_u64::<NetworkEndian>(0).unwrap();
+
+        Attribute::MappedAddress("127.0.0.1:1234".parse().unwrap()).encode(&mut buf).unwrap();
+        Attribute::SourceAddress("127.0.0.1:3478".parse().unwrap()).encode(&mut buf).unwrap();
+        Attribute::ChangedAddress("127.0.0.1:3479".parse().unwrap()).encode(&mut buf).unwrap();
+
+        // No MESSAGE-INTEGRITY attribute at all, simulating an off-path
+        // attacker who forges a response without signing it rather than
+        // trying to produce a matching HMAC.
+        let len = (buf.len() - 20) as u16;
+        StunCodec::set_msg_length(&mut buf, 0, len);
+
+        let mut codec = StunCodec::new().with_credential(credential);
+        let from = "127.0.0.1:3478".parse().unwrap();
+
+        match codec.decode(&from, &buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            other => panic!("expected a rejected unsigned response, got {:?}", other.map(|_| ())),
+        }
     }
 }
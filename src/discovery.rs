@@ -0,0 +1,392 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use tokio_core::net::UdpCodec;
+
+use codec::BindRequest;
+use codec::BindResponse;
+use codec::ChangeRequest;
+use codec::Request;
+use codec::Response;
+use codec::StunCodec;
+
+/// How many times in total a test is sent before it is treated as
+/// unreachable if none of them is answered.
+#[cfg(not(test))]
+const MAX_RETRIES: u32 = 9;
+#[cfg(not(test))]
+const RETRANSMIT_TIMEOUT_MS: u64 = 500;
+
+// Kept short under test so exercising the "unreachable"/no-response branches
+// of the decision tree against a loopback fake server doesn't make the
+// suite slow; the retry/timeout values themselves aren't what these tests
+// are checking.
+#[cfg(test)]
+const MAX_RETRIES: u32 = 3;
+#[cfg(test)]
+const RETRANSMIT_TIMEOUT_MS: u64 = 20;
+
+/// Result of the classic RFC 3489 section 10.1 NAT discovery algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    UdpBlocked,
+    OpenInternet,
+    SymmetricUdpFirewall,
+    FullCone,
+    RestrictedCone,
+    PortRestrictedCone,
+    Symmetric,
+}
+
+/// Drives the Test I/II/III decision tree against a single STUN server,
+/// using `ChangeRequest` to ask the server to answer from its alternate
+/// IP and/or port.
+pub struct NatDiscovery {
+    socket: UdpSocket,
+    server: SocketAddr,
+    codec: StunCodec,
+    next_trans_id: u64,
+}
+
+impl NatDiscovery {
+    /// `socket` must already be bound to a concrete, non-wildcard local
+    /// address (e.g. a specific interface IP), not `0.0.0.0`/`::`. Test I's
+    /// MAPPED-ADDRESS is compared against `socket.local_addr()` to tell
+    /// `OpenInternet`/`SymmetricUdpFirewall` apart from the NAT'd branches,
+    /// and a wildcard address can never equal the external address a STUN
+    /// server reflects back -- those two outcomes collapse into the NAT
+    /// branches instead.
+    pub fn new(socket: UdpSocket, server: SocketAddr) -> NatDiscovery {
+        NatDiscovery {
+            socket: socket,
+            server: server,
+            codec: StunCodec::new(),
+            next_trans_id: 0,
+        }
+    }
+
+    pub fn discover(&mut self) -> Result<NatType> {
+        let local_addr = self.socket.local_addr()?;
+
+        let test1 = match self.bind(self.server, None)? {
+            Some(r) => r,
+            None => return Ok(NatType::UdpBlocked),
+        };
+
+        if test1.mapped_address == local_addr {
+            return Ok(if self.bind(self.server, Some(ChangeRequest::IpAndPort))?.is_some() {
+                NatType::OpenInternet
+            } else {
+                NatType::SymmetricUdpFirewall
+            });
+        }
+
+        if self.bind(self.server, Some(ChangeRequest::IpAndPort))?.is_some() {
+            return Ok(NatType::FullCone);
+        }
+
+        let test1b = match self.bind(test1.changed_address, None)? {
+            Some(r) => r,
+            None => return Err(Error::new(ErrorKind::TimedOut, "No response from server's changed address!")),
+        };
+
+        if test1b.mapped_address != test1.mapped_address {
+            return Ok(NatType::Symmetric);
+        }
+
+        Ok(if self.bind(self.server, Some(ChangeRequest::Port))?.is_some() {
+            NatType::RestrictedCone
+        } else {
+            NatType::PortRestrictedCone
+        })
+    }
+
+    /// Sends a Bind request to `dst` and waits for a matching response,
+    /// sending it up to `MAX_RETRIES` times in all. Returns `None` if the test
+    /// goes entirely unanswered, which is itself meaningful (e.g. Test II's
+    /// response arrives from an address this socket was never sent to).
+    fn bind(&mut self, dst: SocketAddr, change_request: Option<ChangeRequest>) -> Result<Option<BindResponse>> {
+        self.next_trans_id += 1;
+        let trans_id = self.next_trans_id;
+
+        let req = BindRequest { change_request: change_request, ..BindRequest::default() };
+
+        let mut out = Vec::new();
+        self.codec.encode((trans_id, dst, Request::Bind(req)), &mut out);
+
+        self.socket.set_read_timeout(Some(Duration::from_millis(RETRANSMIT_TIMEOUT_MS)))?;
+
+        let mut buf = [0; 512];
+        for _ in 0..MAX_RETRIES {
+            self.socket.send_to(&out, dst)?;
+
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+
+            match self.codec.decode(&from, &buf[..len]) {
+                Ok((id, Response::Bind(r))) if id == trans_id => return Ok(Some(r)),
+                _ => continue,
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use byteorder::ByteOrder;
+    use byteorder::NetworkEndian;
+    use byteorder::WriteBytesExt;
+
+    use view::MessageView;
+
+    // Wire values `Attribute::ChangeRequest` encodes to; not exported by
+    // `codec`, so duplicated here to recognize which of Test I/II/III a
+    // request is without needing a server-side STUN decoder.
+    const CHANGE_REQUEST_TYPE: u16 = 0x0003;
+    const CHANGE_REQUEST_PORT: u32 = 0x40;
+    const CHANGE_REQUEST_IP_AND_PORT: u32 = 0x60;
+    const BINDING_RESPONSE_TYPE: u16 = 0x0101;
+
+    /// A loopback stand-in for a STUN server, scripted per test to answer
+    /// (or silently ignore) each of Test I/II/III. `respond` is given the
+    /// request's actual source address -- which is the discovering socket's
+    /// real local address, letting "OpenInternet" tests reflect it back
+    /// without needing to know the OS-assigned ephemeral port ahead of
+    /// time -- and which kind of request it was (`None` for a plain Test I,
+    /// `Some(CHANGE_REQUEST_IP_AND_PORT)` / `Some(CHANGE_REQUEST_PORT)` for
+    /// Test II/III).
+    struct FakeServer {
+        addr: SocketAddr,
+    }
+
+    impl FakeServer {
+        fn spawn<F>(respond: F) -> FakeServer
+            where F: Fn(SocketAddr, Option<u32>) -> Option<BindResponse> + Send + 'static
+        {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let addr = socket.local_addr().unwrap();
+
+            thread::spawn(move || {
+                let mut buf = [0; 512];
+                loop {
+                    let (len, from) = match socket.recv_from(&mut buf) {
+                        Ok(r) => r,
+                        Err(_) => return,
+                    };
+
+                    let change_request = change_request_of(&buf[..len]);
+
+                    if let Some(r) = respond(from, change_request) {
+                        let out = encode_bind_response(&buf[..len], r);
+                        let _ = socket.send_to(&out, from);
+                    }
+                }
+            });
+
+            FakeServer { addr: addr }
+        }
+    }
+
+    /// Reads the CHANGE-REQUEST attribute out of an encoded `BindRequest`,
+    /// if any -- using `view::MessageView` directly since the fake server
+    /// is answering requests, which `codec::Repr::parse` doesn't handle.
+    fn change_request_of(msg: &[u8]) -> Option<u32> {
+        let view = MessageView::new(msg).ok()?;
+
+        for attr in view.attributes() {
+            let attr = attr.ok()?;
+            if attr.typ == CHANGE_REQUEST_TYPE {
+                return Some(NetworkEndian::read_u32(attr.body));
+            }
+        }
+
+        None
+    }
+
+    /// Builds a BINDING_RESPONSE that echoes `request`'s transaction ID,
+    /// using `codec::Repr::emit` for the body so the fake server round-trips
+    /// through the same attribute encoding a real response would.
+    fn encode_bind_response(request: &[u8], r: BindResponse) -> Vec<u8> {
+        let mut body = Vec::new();
+        Response::Bind(r).emit(&mut body).unwrap();
+
+        let mut out = Vec::with_capacity(20 + body.len());
+        out.write_u16::<NetworkEndian>(BINDING_RESPONSE_TYPE).unwrap();
+        out.write_u16::<NetworkEndian>(body.len() as u16).unwrap();
+        out.extend_from_slice(&request[4..20]); // echo the transaction ID
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn bind_response(mapped: SocketAddr, source: SocketAddr, changed: SocketAddr) -> BindResponse {
+        BindResponse {
+            mapped_address: mapped,
+            source_address: source,
+            changed_address: changed,
+            reflected_from: None,
+        }
+    }
+
+    fn discoverer(server: SocketAddr) -> NatDiscovery {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        NatDiscovery::new(socket, server)
+    }
+
+    // A fixed address used wherever a response needs to carry *some*
+    // SOURCE-ADDRESS/CHANGED-ADDRESS, but `discover()`'s branch under test
+    // never dials it.
+    const UNUSED_ADDR: &str = "198.51.100.1:3478";
+
+    #[test]
+    fn udp_blocked_when_test1_goes_unanswered() {
+        let server = FakeServer::spawn(|_, _| None);
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::UdpBlocked);
+    }
+
+    #[test]
+    fn open_internet_when_unmapped_and_test2_answers() {
+        let server = FakeServer::spawn(|from, change_request| {
+            match change_request {
+                None => Some(bind_response(from, from, UNUSED_ADDR.parse().unwrap())),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => Some(bind_response(from, from, from)),
+                _ => None,
+            }
+        });
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::OpenInternet);
+    }
+
+    #[test]
+    fn symmetric_udp_firewall_when_unmapped_and_test2_silent() {
+        let server = FakeServer::spawn(|from, change_request| {
+            match change_request {
+                None => Some(bind_response(from, from, UNUSED_ADDR.parse().unwrap())),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => None,
+                _ => None,
+            }
+        });
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::SymmetricUdpFirewall);
+    }
+
+    #[test]
+    fn full_cone_when_mapped_and_test2_answers() {
+        let mapped = "203.0.113.5:40000".parse().unwrap();
+
+        let server = FakeServer::spawn(move |from, change_request| {
+            match change_request {
+                None => Some(bind_response(mapped, from, UNUSED_ADDR.parse().unwrap())),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => Some(bind_response(mapped, from, from)),
+                _ => None,
+            }
+        });
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::FullCone);
+    }
+
+    #[test]
+    fn symmetric_when_mapped_address_differs_on_changed_server() {
+        let mapped = "203.0.113.5:40000".parse().unwrap();
+        let remapped = "203.0.113.5:40001".parse().unwrap();
+
+        let changed = FakeServer::spawn(move |from, _| {
+            Some(bind_response(remapped, from, from))
+        });
+        let changed_addr = changed.addr;
+
+        let server = FakeServer::spawn(move |from, change_request| {
+            match change_request {
+                None => Some(bind_response(mapped, from, changed_addr)),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => None,
+                _ => None,
+            }
+        });
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::Symmetric);
+    }
+
+    #[test]
+    fn restricted_cone_when_remapped_same_and_test3_answers() {
+        let mapped = "203.0.113.5:40000".parse().unwrap();
+
+        let changed = FakeServer::spawn(move |from, _| {
+            Some(bind_response(mapped, from, from))
+        });
+        let changed_addr = changed.addr;
+
+        let server = FakeServer::spawn(move |from, change_request| {
+            match change_request {
+                None => Some(bind_response(mapped, from, changed_addr)),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => None,
+                Some(CHANGE_REQUEST_PORT) => Some(bind_response(mapped, from, from)),
+                _ => None,
+            }
+        });
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::RestrictedCone);
+    }
+
+    #[test]
+    fn port_restricted_cone_when_remapped_same_and_test3_silent() {
+        let mapped = "203.0.113.5:40000".parse().unwrap();
+
+        let changed = FakeServer::spawn(move |from, _| {
+            Some(bind_response(mapped, from, from))
+        });
+        let changed_addr = changed.addr;
+
+        let server = FakeServer::spawn(move |from, change_request| {
+            match change_request {
+                None => Some(bind_response(mapped, from, changed_addr)),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => None,
+                Some(CHANGE_REQUEST_PORT) => None,
+                _ => None,
+            }
+        });
+        let mut nat = discoverer(server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::PortRestrictedCone);
+    }
+
+    // Documents the limitation noted on `NatDiscovery::new`: a socket bound
+    // to the wildcard address can never have `local_addr()` equal the real
+    // external address a STUN server reflects back, so a scenario that
+    // would otherwise classify as `OpenInternet` collapses into `FullCone`
+    // instead. Callers must bind to a concrete local address to get an
+    // accurate result.
+    #[test]
+    fn wildcard_bound_socket_misclassifies_open_internet_as_full_cone() {
+        let server = FakeServer::spawn(|from, change_request| {
+            match change_request {
+                None => Some(bind_response(from, from, UNUSED_ADDR.parse().unwrap())),
+                Some(CHANGE_REQUEST_IP_AND_PORT) => Some(bind_response(from, from, from)),
+                _ => None,
+            }
+        });
+
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let mut nat = NatDiscovery::new(socket, server.addr);
+
+        assert_eq!(nat.discover().unwrap(), NatType::FullCone);
+    }
+}
@@ -0,0 +1,9 @@
+extern crate byteorder;
+extern crate crc;
+extern crate md5;
+extern crate ring;
+extern crate tokio_core;
+
+pub mod codec;
+pub mod discovery;
+pub mod view;
@@ -0,0 +1,146 @@
+// Borrowed, zero-copy view over a STUN message; `codec::Repr` builds the
+// owned, semantic representation on top of this.
+
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use byteorder::ByteOrder;
+use byteorder::NetworkEndian;
+
+// Fixed STUN header: 2-byte message type, 2-byte length, 128-bit transaction ID.
+pub const HEADER_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MessageView<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> MessageView<'a> {
+    // Checks the fixed header is present and the declared length matches
+    // `buf`; individual attributes aren't validated until `attributes()` is walked.
+    pub fn new(buf: &'a [u8]) -> Result<MessageView<'a>> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "STUN header truncated"));
+        }
+
+        let view = MessageView { buf };
+
+        if view.length() as usize != buf.len() - HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "STUN message length does not match buffer"));
+        }
+
+        Ok(view)
+    }
+
+    pub fn msg_type(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buf[0..2])
+    }
+
+    pub fn length(&self) -> u16 {
+        NetworkEndian::read_u16(&self.buf[2..4])
+    }
+
+    pub fn transaction_id(&self) -> &'a [u8] {
+        &self.buf[4..HEADER_LEN]
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn attributes(&self) -> Attributes<'a> {
+        Attributes { buf: self.buf, pos: HEADER_LEN }
+    }
+}
+
+// `start` is the TLV header's byte offset in the message, needed to verify
+// MESSAGE-INTEGRITY/FINGERPRINT; `body` has the trailing padding stripped.
+#[derive(Debug)]
+pub struct RawAttribute<'a> {
+    pub typ: u16,
+    pub start: usize,
+    pub body: &'a [u8],
+}
+
+pub struct Attributes<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = Result<RawAttribute<'a>>;
+
+    fn next(&mut self) -> Option<Result<RawAttribute<'a>>> {
+        if self.pos == self.buf.len() {
+            return None;
+        }
+
+        if self.pos + 4 > self.buf.len() {
+            self.pos = self.buf.len();
+            return Some(Err(Error::new(ErrorKind::UnexpectedEof, "Truncated attribute header")));
+        }
+
+        let start = self.pos;
+        let typ = NetworkEndian::read_u16(&self.buf[start..start + 2]);
+        let len = NetworkEndian::read_u16(&self.buf[start + 2..start + 4]) as usize;
+        let padding = (4 - len % 4) % 4;
+
+        let body_start = start + 4;
+        let body_end = body_start + len;
+
+        if body_end + padding > self.buf.len() {
+            self.pos = self.buf.len();
+            return Some(Err(Error::new(ErrorKind::UnexpectedEof, "Truncated attribute body")));
+        }
+
+        self.pos = body_end + padding;
+
+        Some(Ok(RawAttribute { typ, start, body: &self.buf[body_start..body_end] }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_truncated_header() {
+        let buf = [0x00, 0x01, 0x00, 0x00, 0x00];
+        match MessageView::new(&buf) {
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_length_mismatch() {
+        let mut buf = [0u8; 24];
+        NetworkEndian::write_u16(&mut buf[2..4], 8); // claims 8 bytes of attributes, only 4 follow
+        match MessageView::new(&buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            other => panic!("expected InvalidData, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn walks_attributes() {
+        let buf = [
+            0x01, 0x01, 0x00, 0x08, // type, len
+            0x00, 0x00, 0x00, 0x00, // transaction id
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x00, 0x00, 0x00, //  ...
+            0x00, 0x06, 0x00, 0x01, // username, len 1 (padded to 4)
+            0x61, 0x00, 0x00, 0x00, //  "a" + padding
+        ];
+
+        let view = MessageView::new(&buf).unwrap();
+        let attrs: Vec<_> = view.attributes().collect::<Result<_>>().unwrap();
+
+        assert_eq!(attrs.len(), 1);
+        assert_eq!(attrs[0].typ, 0x0006);
+        assert_eq!(attrs[0].start, 20);
+        assert_eq!(attrs[0].body, &[0x61]);
+    }
+}